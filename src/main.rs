@@ -24,19 +24,209 @@ use actix_web::{
     web::{self, Data, Json},
     App, Error, HttpResponse, HttpServer,
 };
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use std::{collections::HashMap, process::exit};
 
+mod metrics;
+mod queue;
+mod routing;
+mod store;
+use metrics::Metrics;
+use queue::DispatchKind;
+use routing::RoutingConfig;
+use store::AlertStateStore;
+use tokio::sync::mpsc;
+
 const COLOR_GRAY: i32 = 9807270;
 const COLOR_RED: i32 = 15145498;
 const COLOR_YELLOW: i32 = 15646767;
 const COLOR_BLUE: i32 = 7782616;
+const COLOR_GREEN: i32 = 5763719;
+
+/// Default TTL, in seconds, for tracked firing-alert state before it's purged as stale.
+const DEFAULT_ALERT_STATE_TTL_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// How many dispatch jobs can sit in the in-process channel awaiting a worker. Jobs are persisted
+/// to the alert state database before being queued, so this just bounds in-memory backlog.
+const DISPATCH_QUEUE_CAPACITY: usize = 1024;
+
+/// Header Alertmanager (or whatever's fronting it) is expected to sign requests with.
+/// Can be overridden with `WEBHOOK_SIGNING_HEADER` if the sender uses something else.
+const DEFAULT_SIGNING_HEADER: &str = "X-Signature-256";
+
+/// Verifies `raw_body` against the `sha256=<hex>` value of `signature_header`, if a signing
+/// secret has been configured. Returns `Ok(())` when verification passes or is not configured
+/// (no secret set means we preserve today's open-webhook behavior), and an error response
+/// otherwise.
+///
+/// This has to run before `Json<AlertManager>` deserialization, since the MAC is computed over
+/// the exact bytes that were sent, not over whatever serde_json would re-serialize them as.
+fn verify_signature(
+    secret: &Option<String>,
+    header_name: &str,
+    headers: &actix_web::http::header::HeaderMap,
+    raw_body: &[u8],
+) -> Result<(), Error> {
+    let secret = match secret {
+        Some(s) => s,
+        None => return Ok(()), // no secret configured, keep legacy open behavior
+    };
+
+    let header_value = headers
+        .get(header_name)
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| {
+            log::warn!("Rejecting request: missing {} header", header_name);
+            error::ErrorUnauthorized("missing signature header")
+        })?;
+
+    let hex_sig = header_value.strip_prefix("sha256=").ok_or_else(|| {
+        log::warn!("Rejecting request: malformed {} header", header_name);
+        error::ErrorUnauthorized("malformed signature header")
+    })?;
+
+    let sig_bytes = hex::decode(hex_sig).map_err(|_| {
+        log::warn!("Rejecting request: non-hex signature");
+        error::ErrorUnauthorized("malformed signature header")
+    })?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(raw_body);
+
+    // `verify_slice` does a constant-time comparison internally.
+    mac.verify_slice(&sig_bytes).map_err(|_| {
+        log::warn!("Rejecting request: signature mismatch");
+        error::ErrorUnauthorized("signature mismatch")
+    })
+}
+
+/// `web::Json<T>` used to enforce `Content-Type: application/json` on the way in. Switching to
+/// raw `web::Bytes` (so `verify_signature` can see the exact signed bytes) dropped that check for
+/// free, so it's reproduced here: any `; charset=...` parameter is ignored, matching what the
+/// `Json` extractor accepted.
+fn ensure_json_content_type(headers: &actix_web::http::header::HeaderMap) -> Result<(), Error> {
+    let content_type = headers
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+
+    if media_type.eq_ignore_ascii_case("application/json") {
+        Ok(())
+    } else {
+        log::warn!(
+            "Rejecting request with unsupported content type: {}",
+            content_type
+        );
+        Err(error::ErrorUnsupportedMediaType(format!(
+            "expected application/json, got '{}'",
+            content_type
+        )))
+    }
+}
+
+/// Alertmanager sends `status` as a bare string, but only ever `"firing"` or `"resolved"` in
+/// practice. Deserializing into this enum instead means a malformed or future Alertmanager
+/// status lands in `Unknown` rather than 400-ing the whole request.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+enum AlertStatus {
+    Firing,
+    Resolved,
+    #[serde(other)]
+    Unknown,
+}
+
+impl AlertStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AlertStatus::Firing => "firing",
+            AlertStatus::Resolved => "resolved",
+            AlertStatus::Unknown => "unknown",
+        }
+    }
+}
+
+impl std::fmt::Display for AlertStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// An Alertmanager timestamp. We parse it into a real `DateTime<Utc>` when we can, but keep the
+/// original string around when we can't, so a non-conformant Alertmanager version doesn't turn
+/// into a hard failure.
+#[derive(Debug, Clone)]
+enum AlertTimestamp {
+    Parsed(chrono::DateTime<chrono::Utc>),
+    Raw(String),
+}
+
+impl AlertTimestamp {
+    fn as_datetime(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        match self {
+            AlertTimestamp::Parsed(dt) => Some(*dt),
+            AlertTimestamp::Raw(_) => None,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for AlertTimestamp {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let raw = String::deserialize(deserializer)?;
+        match chrono::DateTime::parse_from_rfc3339(&raw) {
+            Ok(dt) => Ok(AlertTimestamp::Parsed(dt.with_timezone(&chrono::Utc))),
+            Err(_) => Ok(AlertTimestamp::Raw(raw)),
+        }
+    }
+}
+
+impl Serialize for AlertTimestamp {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            AlertTimestamp::Parsed(dt) => serializer.serialize_str(&dt.to_rfc3339()),
+            AlertTimestamp::Raw(raw) => serializer.serialize_str(raw),
+        }
+    }
+}
+
+/// Renders a `chrono::Duration` the way an operator would say it out loud: `"12m"`, `"1h4m"`,
+/// `"2d"`. Negative durations (clock skew, a malformed timestamp) are floored to zero.
+fn humanize_duration(duration: chrono::Duration) -> String {
+    let total_minutes = duration.num_minutes().max(0);
+    let days = total_minutes / (24 * 60);
+    let hours = (total_minutes / 60) % 24;
+    let minutes = total_minutes % 60;
+
+    let mut rendered = String::new();
+    if days > 0 {
+        rendered.push_str(&format!("{}d", days));
+    }
+    if hours > 0 {
+        rendered.push_str(&format!("{}h", hours));
+    }
+    if minutes > 0 || rendered.is_empty() {
+        rendered.push_str(&format!("{}m", minutes));
+    }
+
+    rendered
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 struct AlertManager {
     version: String,
     groupKey: String,
-    status: String, // TODO: this can be changed to an enum Resolved/Firing
+    status: AlertStatus,
     receiver: String,
     commonLabels: HashMap<String, String>,
     commonAnnotations: HashMap<String, String>,
@@ -46,11 +236,11 @@ struct AlertManager {
 
 #[derive(Debug, Serialize, Deserialize)]
 struct AlertManagerAlert {
-    status: String, // TODO: change to an enum Resolved/Firing
+    status: AlertStatus,
     labels: HashMap<String, String>,
     annotations: HashMap<String, String>,
-    startsAt: String, // TODO: this can be parsed out with chrono
-    endsAt: String,   // TODO: this can be parsed out with chrono
+    startsAt: AlertTimestamp,
+    endsAt: AlertTimestamp,
     generatorURL: String,
 }
 
@@ -86,18 +276,47 @@ struct ReadyzQueryParams {
     verbose: Option<String>,
 }
 
+/// Configuration for verifying inbound webhook signatures. Lives in `app_data` alongside the
+/// routing config.
+struct WebhookSigningConfig {
+    secret: Option<String>,
+    header_name: String,
+}
+
 async fn index(
-    item: Json<AlertManager>,
-    webhook: web::Data<String>,
+    req: actix_web::HttpRequest,
+    body: web::Bytes,
+    routing: web::Data<RoutingConfig>,
+    signing_config: web::Data<WebhookSigningConfig>,
+    store: web::Data<AlertStateStore>,
+    metrics: web::Data<Metrics>,
+    dispatch_tx: web::Data<mpsc::Sender<queue::DispatchJob>>,
 ) -> Result<HttpResponse, Error> {
+    ensure_json_content_type(req.headers())?;
+
+    verify_signature(
+        &signing_config.secret,
+        &signing_config.header_name,
+        req.headers(),
+        &body,
+    )?;
+
+    let item = Json::<AlertManager>(
+        serde_json::from_slice(&body).map_err(error::ErrorBadRequest)?,
+    );
+
     log::debug!("Incoming payload: {:?}", &item);
 
+    metrics
+        .alerts_received_total
+        .inc_by(item.alerts.len() as u64);
+
     // run through the incoming alerts and group them by status and severity
-    // HashMap<"status", HashMap<"severity", Vec<AlertManagerAlert>>>
-    let mut grouped_alerts = HashMap::<&str, HashMap<&str, Vec<&AlertManagerAlert>>>::new();
+    // HashMap<AlertStatus, HashMap<"severity", Vec<AlertManagerAlert>>>
+    let mut grouped_alerts = HashMap::<AlertStatus, HashMap<&str, Vec<&AlertManagerAlert>>>::new();
 
     for alert in &item.alerts {
-        let alerts_by_severity = grouped_alerts.entry(&alert.status).or_default();
+        let alerts_by_severity = grouped_alerts.entry(alert.status).or_default();
 
         let severity = match alert.labels.get("severity") {
             Some(s) => s.as_str(),
@@ -106,17 +325,18 @@ async fn index(
 
         // don't alert on "none" severity alerts. They don't matter.
         if severity == "none" {
+            metrics.alerts_dropped_total.inc();
             continue;
         }
 
         alerts_by_severity.entry(severity).or_default().push(alert);
     }
 
-    let client = reqwest::Client::new();
-
     // in general, the difference between a firing alert and a resolved alert is minor, just a couple of small text differences.
     // So we can handle them in a for loop.
     for (status, alerts_by_severity) in grouped_alerts {
+        let severities: Vec<&str> = alerts_by_severity.keys().copied().collect();
+
         let embeds = alerts_by_severity
             .into_iter()
             .map(|(severity, alerts)| {
@@ -130,7 +350,7 @@ async fn index(
                             .get("alertname")
                             .cloned()
                             .unwrap_or_else(|| "No-name alert".to_string());
-                        let value = alert
+                        let description = alert
                             .annotations
                             .get("description")
                             .cloned()
@@ -142,6 +362,33 @@ async fn index(
                                     .unwrap_or_default()
                             });
 
+                        // Let operators triage at a glance: how long has this been firing, or
+                        // how long did it take to resolve?
+                        let duration_note = match status {
+                            AlertStatus::Firing => alert.startsAt.as_datetime().map(|starts_at| {
+                                format!(
+                                    "_firing for {}_",
+                                    humanize_duration(chrono::Utc::now() - starts_at)
+                                )
+                            }),
+                            AlertStatus::Resolved => alert
+                                .startsAt
+                                .as_datetime()
+                                .zip(alert.endsAt.as_datetime())
+                                .map(|(starts_at, ends_at)| {
+                                    format!(
+                                        "_resolved after {}_",
+                                        humanize_duration(ends_at - starts_at)
+                                    )
+                                }),
+                            AlertStatus::Unknown => None,
+                        };
+
+                        let value = match duration_note {
+                            Some(note) => format!("{}\n{}", description, note),
+                            None => description,
+                        };
+
                         DiscordEmbedField { name, value }
                     })
                     .collect::<Vec<DiscordEmbedField>>();
@@ -179,78 +426,132 @@ async fn index(
             })
             .collect::<Vec<_>>();
 
-        let discord = Discord {
+        let mut discord = Discord {
             content: match status {
-                "firing" => "🚨 Your infrastructure would like to inform you about some stuff! 🚨"
-                    .to_string(),
-                "resolved" => "🎉 These issues have been resolved! 🎉".to_string(),
-                _ => format!("Unknown status {}, please advise!", status),
+                AlertStatus::Firing => {
+                    "🚨 Your infrastructure would like to inform you about some stuff! 🚨"
+                        .to_string()
+                }
+                AlertStatus::Resolved => "🎉 These issues have been resolved! 🎉".to_string(),
+                AlertStatus::Unknown => format!("Unknown status {}, please advise!", status),
             },
             embeds,
         };
 
         if discord.embeds.is_empty() {
-            log::debug!("No alerts to send, skipping!");
-            return Ok(HttpResponse::Ok().finish());
+            log::debug!("No alerts to send for status {}, skipping!", status);
+            continue;
         }
 
+        let webhook = routing.route(&item.receiver, status.as_str(), &item.commonLabels);
+
+        // A resolved embed is always green, regardless of how severe the alert was while firing.
+        if status == AlertStatus::Resolved {
+            for embed in discord.embeds.iter_mut() {
+                embed.color = COLOR_GREEN;
+            }
+        }
+
+        // If this status resolves a group we've previously posted a firing embed for, edit that
+        // message in place instead of sending a brand new one.
+        // Only peek at the mapping here, rather than consuming it: the patch job hasn't actually
+        // been dispatched yet. The worker clears the mapping once the patch succeeds, or once it
+        // gives up on the patch after exhausting its retries.
+        let existing_message_id = if status == AlertStatus::Resolved {
+            store.peek_message_id(&item.groupKey).unwrap_or_else(|e| {
+                log::warn!("Could not look up alert state for groupKey: {}", e);
+                None
+            })
+        } else {
+            None
+        };
+
+        let kind = match existing_message_id {
+            Some(message_id) => DispatchKind::Patch { message_id },
+            None if status == AlertStatus::Firing => DispatchKind::PostCapturingId,
+            None => DispatchKind::PostPlain,
+        };
+
         log::debug!(
-            "Sending discord payload to webhook: {:?}",
+            "Queueing discord payload for webhook: {:?}",
             serde_json::to_string(&discord)
         );
 
-        match client.post(webhook.get_ref()).json(&discord).send().await {
-            Err(e) => {
-                log::error!("Could not send to Discord: {}", e);
-                return Err(error::ErrorInternalServerError("Could not send to Discord"));
-            }
-            Ok(res) => {
-                if let Err(e) = res.error_for_status() {
-                    log::error!("Discord API returned error: {}", e);
-                    return Err(error::ErrorInternalServerError(
-                        "Discord API rejected payload",
-                    ));
-                }
-            }
+        let payload = serde_json::to_value(&discord).map_err(error::ErrorInternalServerError)?;
+        let job_severities: Vec<String> = severities.iter().map(|s| s.to_string()).collect();
+
+        if let Err(e) = queue::enqueue(
+            &store,
+            &dispatch_tx,
+            webhook.to_string(),
+            kind,
+            item.groupKey.clone(),
+            payload,
+            status.as_str().to_string(),
+            job_severities,
+        )
+        .await
+        {
+            log::error!("Could not persist alert for dispatch: {}", e);
+            return Err(error::ErrorInternalServerError(
+                "Could not queue alert for dispatch",
+            ));
         }
     }
 
-    log::info!("Dispatched alerts to Discord");
+    log::info!("Queued alerts for dispatch to Discord");
 
     Ok(HttpResponse::Ok().finish())
 }
 
+/// Exposes this service's own metrics in Prometheus text format.
+async fn metrics_handler(metrics: web::Data<Metrics>) -> Result<HttpResponse, Error> {
+    use prometheus::{Encoder, TextEncoder};
+
+    let encoder = TextEncoder::new();
+    let metric_families = metrics.registry.gather();
+
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&metric_families, &mut buffer)
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buffer))
+}
+
 /// Tests all necessary upstream components to make sure that the service is ready to accept messages.
 async fn readyz(
     query: web::Query<ReadyzQueryParams>,
-    webhook: web::Data<String>,
+    routing: web::Data<RoutingConfig>,
 ) -> Result<HttpResponse, Error> {
     let mut component_statuses = HashMap::new();
 
-    // test connectivity to Discord.
-    let discord_success = {
-        let test_req = reqwest::get(webhook.get_ref()).await;
+    // test connectivity to every webhook this instance could possibly route to.
+    for (name, webhook) in routing.named_webhooks() {
+        let test_req = reqwest::get(webhook).await;
 
-        // set the value of discord_success based on the response code of the call to Discord.
-        match test_req {
+        let success = match test_req {
             Ok(res) => {
                 if res.status() == reqwest::StatusCode::OK {
                     true
                 } else {
                     match res.text().await {
-                        Ok(s) => log::warn!("Error talking to Discord: {}", s),
-                        Err(_) => log::warn!("Error talking to Discord"),
+                        Ok(s) => log::warn!("Error talking to Discord ({}): {}", name, s),
+                        Err(_) => log::warn!("Error talking to Discord ({})", name),
                     };
                     false
                 }
             }
             Err(e) => {
-                log::warn!("Discord not reachable: {}", e);
+                log::warn!("Discord webhook ({}) not reachable: {}", name, e);
                 false
             }
-        }
-    };
-    component_statuses.insert("Discord", discord_success);
+        };
+
+        component_statuses.insert(format!("Discord ({})", name), success);
+    }
 
     // generate response. If "?verbose" is passed as a query parameter, generate a verbose string.
     if query.0.verbose.is_some() {
@@ -291,27 +592,180 @@ async fn main() -> std::io::Result<()> {
     let listen_addr =
         std::env::var("LISTEN_ADDRESS").unwrap_or_else(|_| "127.0.0.1:9094".to_string());
 
-    HttpServer::new(|| {
+    let signing_secret = std::env::var("WEBHOOK_SIGNING_SECRET")
+        .ok()
+        .filter(|s| !s.is_empty());
+    if signing_secret.is_none() {
+        log::warn!(
+            "WEBHOOK_SIGNING_SECRET not set, incoming webhooks will not be authenticated"
+        );
+    }
+    let signing_header = std::env::var("WEBHOOK_SIGNING_HEADER")
+        .unwrap_or_else(|_| DEFAULT_SIGNING_HEADER.to_string());
+
+    let alert_state_db_path =
+        std::env::var("ALERT_STATE_DB_PATH").unwrap_or_else(|_| "alert_state.db".to_string());
+    let alert_state_ttl_secs = std::env::var("ALERT_STATE_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_ALERT_STATE_TTL_SECS);
+    let alert_state_store = Data::new(
+        AlertStateStore::open(&alert_state_db_path, alert_state_ttl_secs).unwrap_or_else(|e| {
+            log::error!("Could not open alert state database: {}", e);
+            exit(1);
+        }),
+    );
+
+    // ROUTING_CONFIG_PATH supersedes the old single-webhook setup. If it's not set, fall back to
+    // DISCORD_WEBHOOK so an existing deployment doesn't break on upgrade without a config file.
+    let routing_config = Data::new(match std::env::var("ROUTING_CONFIG_PATH") {
+        Ok(path) => RoutingConfig::load(&path).unwrap_or_else(|e| {
+            log::error!("Could not load routing config: {}", e);
+            exit(1);
+        }),
+        Err(_) => {
+            let webhook = std::env::var("DISCORD_WEBHOOK").unwrap_or_else(|_| {
+                log::error!(
+                    "Must set either ROUTING_CONFIG_PATH or DISCORD_WEBHOOK environment variable"
+                );
+                exit(1);
+            });
+            log::warn!(
+                "ROUTING_CONFIG_PATH not set, falling back to single-webhook DISCORD_WEBHOOK config"
+            );
+            RoutingConfig::single_webhook(webhook)
+        }
+    });
+
+    let metrics = Data::new(Metrics::new().unwrap_or_else(|e| {
+        log::error!("Could not set up metrics: {}", e);
+        exit(1);
+    }));
+
+    let (dispatch_tx, dispatch_rx) = mpsc::channel::<queue::DispatchJob>(DISPATCH_QUEUE_CAPACITY);
+
+    // Spawn the worker(s) before resuming anything from disk: `resume_pending` sends one job per
+    // pending row and the channel is bounded, so if nothing is draining it yet and a restart finds
+    // more than `DISPATCH_QUEUE_CAPACITY` rows queued, the send would block forever and the server
+    // would never come up.
+    queue::spawn_workers(
+        dispatch_rx,
+        alert_state_store.clone(),
+        metrics.clone(),
+        reqwest::Client::new(),
+    );
+
+    queue::resume_pending(&alert_state_store, &dispatch_tx).await;
+
+    let dispatch_tx = Data::new(dispatch_tx);
+
+    HttpServer::new(move || {
         App::new()
             .wrap(middleware::Logger::default())
-            .app_data(Data::new(match std::env::var("DISCORD_WEBHOOK") {
-                Ok(webhook) => {
-                    if !webhook.is_empty() {
-                        webhook
-                    } else {
-                        log::error!("Must set DISCORD_WEBHOOK environment variable");
-                        exit(1);
-                    }
-                }
-                Err(_) => {
-                    log::error!("Must set DISCORD_WEBHOOK environment variable");
-                    exit(1);
-                }
+            .app_data(routing_config.clone())
+            .app_data(Data::new(WebhookSigningConfig {
+                secret: signing_secret.clone(),
+                header_name: signing_header.clone(),
             }))
+            .app_data(alert_state_store.clone())
+            .app_data(metrics.clone())
+            .app_data(dispatch_tx.clone())
             .service(web::resource("/").route(web::post().to(index))) // Main handler route. Send Alertmanager here.
             .service(web::resource("/readyz").route(web::get().to(readyz))) // ready check. Point liveness and readiness checks here.
+            .service(web::resource("/metrics").route(web::get().to(metrics_handler))) // Prometheus scrape endpoint.
     })
     .bind(listen_addr)?
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn humanize_duration_renders_minutes_only() {
+        assert_eq!(humanize_duration(chrono::Duration::minutes(12)), "12m");
+    }
+
+    #[test]
+    fn humanize_duration_renders_hours_and_minutes() {
+        assert_eq!(humanize_duration(chrono::Duration::minutes(64)), "1h4m");
+    }
+
+    #[test]
+    fn humanize_duration_renders_days_hours_and_minutes() {
+        assert_eq!(
+            humanize_duration(chrono::Duration::minutes(2 * 24 * 60 + 3 * 60 + 5)),
+            "2d3h5m"
+        );
+    }
+
+    #[test]
+    fn humanize_duration_omits_zero_components() {
+        assert_eq!(humanize_duration(chrono::Duration::hours(2)), "2h");
+    }
+
+    #[test]
+    fn humanize_duration_renders_zero_as_0m() {
+        assert_eq!(humanize_duration(chrono::Duration::zero()), "0m");
+    }
+
+    fn signed_headers(header_name: &str, secret: &str, body: &[u8]) -> actix_web::http::header::HeaderMap {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take a key of any size");
+        mac.update(body);
+        let signature = hex::encode(mac.finalize().into_bytes());
+
+        let mut headers = actix_web::http::header::HeaderMap::new();
+        headers.insert(
+            actix_web::http::header::HeaderName::from_bytes(header_name.as_bytes()).unwrap(),
+            actix_web::http::header::HeaderValue::from_str(&format!("sha256={}", signature)).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn verify_signature_allows_when_no_secret_configured() {
+        let headers = actix_web::http::header::HeaderMap::new();
+        assert!(verify_signature(&None, DEFAULT_SIGNING_HEADER, &headers, b"body").is_ok());
+    }
+
+    #[test]
+    fn verify_signature_accepts_correct_signature() {
+        let body = b"the-request-body";
+        let headers = signed_headers(DEFAULT_SIGNING_HEADER, "shared-secret", body);
+        assert!(verify_signature(
+            &Some("shared-secret".to_string()),
+            DEFAULT_SIGNING_HEADER,
+            &headers,
+            body
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn verify_signature_rejects_mismatched_signature() {
+        let body = b"the-request-body";
+        let headers = signed_headers(DEFAULT_SIGNING_HEADER, "wrong-secret", body);
+        assert!(verify_signature(
+            &Some("shared-secret".to_string()),
+            DEFAULT_SIGNING_HEADER,
+            &headers,
+            body
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn verify_signature_rejects_missing_header() {
+        let headers = actix_web::http::header::HeaderMap::new();
+        assert!(verify_signature(
+            &Some("shared-secret".to_string()),
+            DEFAULT_SIGNING_HEADER,
+            &headers,
+            b"body"
+        )
+        .is_err());
+    }
+}
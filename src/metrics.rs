@@ -0,0 +1,79 @@
+/*
+This file is part of Alertmanager to Discord Bridge (https://github.com/SriRamanujam/alertmanager-discord-bridge)
+Copyright (C) 2021 Sri Ramanujam
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 2 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along
+with this program; if not, write to the Free Software Foundation, Inc.,
+51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA
+*/
+
+use prometheus::{Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry};
+
+/// All of this service's own Prometheus metrics, so operators can alert on the alert-bridge
+/// itself. Lives in `app_data`, the same way the routing config does.
+pub struct Metrics {
+    pub registry: Registry,
+    pub alerts_received_total: IntCounter,
+    pub alerts_dropped_total: IntCounter,
+    pub embeds_dispatched_total: IntCounterVec,
+    pub discord_post_failures_total: IntCounter,
+    pub discord_request_duration_seconds: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new();
+
+        let alerts_received_total = IntCounter::with_opts(Opts::new(
+            "alertbridge_alerts_received_total",
+            "Total number of individual Alertmanager alerts received",
+        ))?;
+        registry.register(Box::new(alerts_received_total.clone()))?;
+
+        let alerts_dropped_total = IntCounter::with_opts(Opts::new(
+            "alertbridge_alerts_dropped_total",
+            "Total number of alerts dropped for having severity=none",
+        ))?;
+        registry.register(Box::new(alerts_dropped_total.clone()))?;
+
+        let embeds_dispatched_total = IntCounterVec::new(
+            Opts::new(
+                "alertbridge_embeds_dispatched_total",
+                "Total number of Discord embeds dispatched, by alert status and severity",
+            ),
+            &["status", "severity"],
+        )?;
+        registry.register(Box::new(embeds_dispatched_total.clone()))?;
+
+        let discord_post_failures_total = IntCounter::with_opts(Opts::new(
+            "alertbridge_discord_post_failures_total",
+            "Total number of failed POST/PATCH requests to Discord",
+        ))?;
+        registry.register(Box::new(discord_post_failures_total.clone()))?;
+
+        let discord_request_duration_seconds = Histogram::with_opts(HistogramOpts::new(
+            "alertbridge_discord_request_duration_seconds",
+            "Round-trip latency of requests to the Discord webhook API",
+        ))?;
+        registry.register(Box::new(discord_request_duration_seconds.clone()))?;
+
+        Ok(Self {
+            registry,
+            alerts_received_total,
+            alerts_dropped_total,
+            embeds_dispatched_total,
+            discord_post_failures_total,
+            discord_request_duration_seconds,
+        })
+    }
+}
@@ -0,0 +1,468 @@
+/*
+This file is part of Alertmanager to Discord Bridge (https://github.com/SriRamanujam/alertmanager-discord-bridge)
+Copyright (C) 2021 Sri Ramanujam
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 2 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along
+with this program; if not, write to the Free Software Foundation, Inc.,
+51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA
+*/
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::web;
+use tokio::sync::{mpsc, Mutex};
+
+use crate::metrics::Metrics;
+use crate::store::AlertStateStore;
+
+/// Bounded attempts before a job is abandoned, counting the first try (and counting towards
+/// rate-limit retries too, not just errors). With the backoff schedule below, a job that keeps
+/// erroring spends about 31 seconds (1s + 2s + 4s + 8s + 16s, plus jitter) waiting between
+/// attempts; rate-limit waits follow Discord's headers instead.
+const MAX_ATTEMPTS: u32 = 6;
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How many worker tasks pull jobs off the shared dispatch queue. A single stuck or rate-limited
+/// webhook only ties up one of these, so alerts bound for every other webhook still get through.
+const WORKER_COUNT: usize = 4;
+
+/// What to do with a job's Discord response.
+#[derive(Debug, Clone)]
+pub enum DispatchKind {
+    /// POST a new message and capture the returned message id, so a later resolution can edit it.
+    PostCapturingId,
+    /// POST a new message; nothing further needs to be tracked.
+    PostPlain,
+    /// PATCH an existing message.
+    Patch { message_id: String },
+}
+
+impl DispatchKind {
+    fn tag(&self) -> &'static str {
+        match self {
+            DispatchKind::PostCapturingId => "post_capturing_id",
+            DispatchKind::PostPlain => "post_plain",
+            DispatchKind::Patch { .. } => "patch",
+        }
+    }
+
+    fn message_id(&self) -> Option<&str> {
+        match self {
+            DispatchKind::Patch { message_id } => Some(message_id),
+            _ => None,
+        }
+    }
+
+    fn from_persisted(tag: &str, message_id: Option<String>) -> Option<Self> {
+        match tag {
+            "post_capturing_id" => Some(DispatchKind::PostCapturingId),
+            "post_plain" => Some(DispatchKind::PostPlain),
+            "patch" => message_id.map(|message_id| DispatchKind::Patch { message_id }),
+            _ => None,
+        }
+    }
+}
+
+/// A single unit of work for the background dispatch worker: one Discord embed payload, bound
+/// for one webhook, plus enough bookkeeping to update alert state and metrics once it lands.
+#[derive(Debug, Clone)]
+pub struct DispatchJob {
+    persisted_id: i64,
+    webhook: String,
+    kind: DispatchKind,
+    group_key: String,
+    payload: serde_json::Value,
+    status: String,
+    severities: Vec<String>,
+}
+
+/// Persists `payload` to the retry queue and hands it to the background worker, then returns.
+/// Dispatch itself happens out of band, so a slow or unreachable Discord webhook never fails the
+/// Alertmanager request that triggered it.
+#[allow(clippy::too_many_arguments)]
+pub async fn enqueue(
+    store: &AlertStateStore,
+    tx: &mpsc::Sender<DispatchJob>,
+    webhook: String,
+    kind: DispatchKind,
+    group_key: String,
+    payload: serde_json::Value,
+    status: String,
+    severities: Vec<String>,
+) -> rusqlite::Result<()> {
+    let severities_json = serde_json::to_string(&severities).unwrap_or_else(|_| "[]".to_string());
+    let persisted_id = store.enqueue_dispatch(
+        &webhook,
+        kind.tag(),
+        kind.message_id(),
+        &group_key,
+        &payload.to_string(),
+        &status,
+        &severities_json,
+    )?;
+
+    let job = DispatchJob {
+        persisted_id,
+        webhook,
+        kind,
+        group_key,
+        payload,
+        status,
+        severities,
+    };
+
+    if tx.send(job).await.is_err() {
+        log::error!("Dispatch worker is not running; job will be retried from disk on next start");
+    }
+
+    Ok(())
+}
+
+/// Loads jobs left over from a previous run (the process stopped before the worker drained the
+/// queue) and feeds them back into the channel so they resume on startup.
+pub async fn resume_pending(store: &AlertStateStore, tx: &mpsc::Sender<DispatchJob>) {
+    let rows = match store.pending_dispatches() {
+        Ok(rows) => rows,
+        Err(e) => {
+            log::error!("Could not load pending dispatch queue: {}", e);
+            return;
+        }
+    };
+
+    if !rows.is_empty() {
+        log::info!("Resuming {} pending Discord dispatch(es) from disk", rows.len());
+    }
+
+    for row in rows {
+        let kind = match DispatchKind::from_persisted(&row.kind, row.message_id) {
+            Some(kind) => kind,
+            None => {
+                log::warn!("Dropping unrecognized persisted dispatch job {}", row.id);
+                continue;
+            }
+        };
+
+        let payload: serde_json::Value = match serde_json::from_str(&row.payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::warn!(
+                    "Dropping persisted dispatch job {} with unparseable payload: {}",
+                    row.id, e
+                );
+                continue;
+            }
+        };
+
+        let severities: Vec<String> = serde_json::from_str(&row.severities).unwrap_or_else(|e| {
+            log::warn!(
+                "Could not parse persisted severities for dispatch job {}, metrics for it will undercount: {}",
+                row.id, e
+            );
+            Vec::new()
+        });
+
+        let job = DispatchJob {
+            persisted_id: row.id,
+            webhook: row.webhook,
+            kind,
+            group_key: row.group_key,
+            payload,
+            status: row.status,
+            severities,
+        };
+
+        if tx.send(job).await.is_err() {
+            log::error!("Dispatch worker is not running; could not resume persisted queue");
+            break;
+        }
+    }
+}
+
+/// Spawns `WORKER_COUNT` tasks that share `rx`, each draining jobs and retrying them on failure.
+/// Spreading jobs across several tasks means a single webhook that's stuck retrying (rate-limited
+/// or down) only occupies one of them, instead of head-of-line-blocking every other queued alert.
+pub fn spawn_workers(
+    rx: mpsc::Receiver<DispatchJob>,
+    store: web::Data<AlertStateStore>,
+    metrics: web::Data<Metrics>,
+    client: reqwest::Client,
+) {
+    let rx = Arc::new(Mutex::new(rx));
+
+    for _ in 0..WORKER_COUNT {
+        let rx = rx.clone();
+        let store = store.clone();
+        let metrics = metrics.clone();
+        let client = client.clone();
+
+        actix_web::rt::spawn(async move {
+            loop {
+                let job = {
+                    let mut rx = rx.lock().await;
+                    rx.recv().await
+                };
+
+                match job {
+                    Some(job) => dispatch_with_retry(&job, &store, &metrics, &client).await,
+                    None => return,
+                }
+            }
+        });
+    }
+}
+
+enum SendOutcome {
+    Success(Option<String>),
+    RateLimited(Duration),
+}
+
+async fn dispatch_with_retry(
+    job: &DispatchJob,
+    store: &AlertStateStore,
+    metrics: &Metrics,
+    client: &reqwest::Client,
+) {
+    let mut attempt = 0u32;
+
+    loop {
+        attempt += 1;
+
+        let timer = metrics.discord_request_duration_seconds.start_timer();
+        let result = send_once(job, client).await;
+        timer.observe_duration();
+
+        match result {
+            Ok(SendOutcome::Success(message_id)) => {
+                match &job.kind {
+                    DispatchKind::PostCapturingId => {
+                        if let Some(id) = message_id {
+                            if let Err(e) = store.record_firing(&job.group_key, &id) {
+                                log::warn!("Could not persist alert state: {}", e);
+                            }
+                        } else {
+                            log::warn!(
+                                "Discord did not return a message id for groupKey {}, won't be able to edit it later",
+                                job.group_key
+                            );
+                        }
+                    }
+                    DispatchKind::Patch { message_id } => {
+                        // The group is resolved and its message has been patched in place; drop
+                        // the mapping now that the patch is known to have succeeded, so a future
+                        // firing of this group starts a fresh message. Scoped to this message id
+                        // so a stale patch that finally lands after the group re-fired doesn't
+                        // clear out the mapping for the new, still-live message.
+                        if let Err(e) = store.clear_message_id(&job.group_key, message_id) {
+                            log::warn!("Could not clear alert state: {}", e);
+                        }
+                    }
+                    DispatchKind::PostPlain => {}
+                }
+
+                for severity in &job.severities {
+                    metrics
+                        .embeds_dispatched_total
+                        .with_label_values(&[&job.status, severity])
+                        .inc();
+                }
+
+                if let Err(e) = store.remove_dispatch(job.persisted_id) {
+                    log::warn!("Could not remove dispatched job from queue: {}", e);
+                }
+
+                return;
+            }
+            Ok(SendOutcome::RateLimited(retry_after)) => {
+                if attempt >= MAX_ATTEMPTS {
+                    log::error!(
+                        "Giving up on Discord dispatch after {} attempts, still rate-limited, dropping message",
+                        attempt
+                    );
+                    abandon(job, store);
+                    return;
+                }
+
+                log::warn!(
+                    "Discord rate-limited us, retrying in {:?} (attempt {}/{})",
+                    retry_after, attempt, MAX_ATTEMPTS
+                );
+                tokio::time::sleep(retry_after).await;
+            }
+            Err(e) => {
+                metrics.discord_post_failures_total.inc();
+
+                if attempt >= MAX_ATTEMPTS {
+                    log::error!(
+                        "Giving up on Discord dispatch after {} attempts, dropping message: {}",
+                        attempt, e
+                    );
+                    abandon(job, store);
+                    return;
+                }
+
+                let backoff = backoff_for_attempt(attempt);
+                log::warn!(
+                    "Discord dispatch failed ({}), retrying in {:?} (attempt {}/{})",
+                    e, backoff, attempt, MAX_ATTEMPTS
+                );
+                tokio::time::sleep(backoff).await;
+            }
+        }
+    }
+}
+
+/// Drops a job that exhausted its retries: removes it from the persisted queue, and, for a
+/// `Patch`, also clears its alert-state mapping. Otherwise a `Patch` that gives up (e.g. because
+/// the original message was deleted and every PATCH 404s) would leave `alert_state` pointing at a
+/// dead message id until its TTL expires, so every resolution of that group in the meantime keeps
+/// trying and failing to patch a message that's gone instead of posting a fresh one.
+fn abandon(job: &DispatchJob, store: &AlertStateStore) {
+    if let DispatchKind::Patch { message_id } = &job.kind {
+        if let Err(e) = store.clear_message_id(&job.group_key, message_id) {
+            log::warn!("Could not clear alert state for abandoned job: {}", e);
+        }
+    }
+
+    if let Err(e) = store.remove_dispatch(job.persisted_id) {
+        log::warn!("Could not remove abandoned job from queue: {}", e);
+    }
+}
+
+async fn send_once(job: &DispatchJob, client: &reqwest::Client) -> Result<SendOutcome, String> {
+    let request = match &job.kind {
+        DispatchKind::PostCapturingId => client.post(format!("{}?wait=true", job.webhook)),
+        DispatchKind::PostPlain => client.post(&job.webhook),
+        DispatchKind::Patch { message_id } => {
+            client.patch(format!("{}/messages/{}", job.webhook, message_id))
+        }
+    };
+
+    let response = request
+        .json(&job.payload)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        return Ok(SendOutcome::RateLimited(retry_after_duration(
+            response.headers(),
+        )));
+    }
+
+    let response = response.error_for_status().map_err(|e| e.to_string())?;
+
+    if matches!(job.kind, DispatchKind::PostCapturingId) {
+        let body: serde_json::Value = response.json().await.map_err(|e| e.to_string())?;
+        let message_id = body.get("id").and_then(|v| v.as_str()).map(str::to_string);
+        return Ok(SendOutcome::Success(message_id));
+    }
+
+    Ok(SendOutcome::Success(None))
+}
+
+/// Honors Discord's rate-limit headers: `Retry-After` (seconds) takes priority, falling back to
+/// `X-RateLimit-Reset-After`. Falls back to the base backoff if Discord didn't send either.
+fn retry_after_duration(headers: &reqwest::header::HeaderMap) -> Duration {
+    let header_secs = headers
+        .get("Retry-After")
+        .or_else(|| headers.get("X-RateLimit-Reset-After"))
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<f64>().ok());
+
+    match header_secs {
+        Some(secs) if secs > 0.0 => Duration::from_secs_f64(secs),
+        _ => BASE_BACKOFF,
+    }
+}
+
+/// Capped exponential backoff (1s, 2s, 4s, ... up to `MAX_BACKOFF`) plus a little jitter so a
+/// burst of failing jobs doesn't all retry in lockstep.
+fn backoff_for_attempt(attempt: u32) -> Duration {
+    let exponent = attempt.saturating_sub(1).min(10);
+    let backoff = BASE_BACKOFF.saturating_mul(1u32 << exponent).min(MAX_BACKOFF);
+    backoff + jitter()
+}
+
+/// 0-250ms of jitter, derived from the clock since this crate has no dependency on `rand`.
+fn jitter() -> Duration {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+
+    Duration::from_millis((nanos % 250) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // jitter() adds up to 250ms on top of the base value, so assert a range rather than an exact
+    // duration.
+    fn assert_in_jitter_range(actual: Duration, base: Duration) {
+        assert!(actual >= base, "{:?} should be >= {:?}", actual, base);
+        assert!(
+            actual < base + Duration::from_millis(250),
+            "{:?} should be < {:?}",
+            actual,
+            base + Duration::from_millis(250)
+        );
+    }
+
+    #[test]
+    fn backoff_for_attempt_starts_at_base_backoff() {
+        assert_in_jitter_range(backoff_for_attempt(1), BASE_BACKOFF);
+    }
+
+    #[test]
+    fn backoff_for_attempt_doubles_each_time() {
+        assert_in_jitter_range(backoff_for_attempt(2), Duration::from_secs(2));
+        assert_in_jitter_range(backoff_for_attempt(3), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn backoff_for_attempt_caps_at_max_backoff() {
+        assert_in_jitter_range(backoff_for_attempt(20), MAX_BACKOFF);
+    }
+
+    fn headers_with(pairs: &[(&str, &str)]) -> reqwest::header::HeaderMap {
+        let mut headers = reqwest::header::HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(
+                reqwest::header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                reqwest::header::HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn retry_after_duration_prefers_retry_after_header() {
+        let headers = headers_with(&[("Retry-After", "5"), ("X-RateLimit-Reset-After", "99")]);
+        assert_eq!(retry_after_duration(&headers), Duration::from_secs_f64(5.0));
+    }
+
+    #[test]
+    fn retry_after_duration_falls_back_to_rate_limit_reset_header() {
+        let headers = headers_with(&[("X-RateLimit-Reset-After", "2.5")]);
+        assert_eq!(retry_after_duration(&headers), Duration::from_secs_f64(2.5));
+    }
+
+    #[test]
+    fn retry_after_duration_falls_back_to_base_backoff_without_headers() {
+        let headers = headers_with(&[]);
+        assert_eq!(retry_after_duration(&headers), BASE_BACKOFF);
+    }
+}
@@ -0,0 +1,212 @@
+/*
+This file is part of Alertmanager to Discord Bridge (https://github.com/SriRamanujam/alertmanager-discord-bridge)
+Copyright (C) 2021 Sri Ramanujam
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 2 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along
+with this program; if not, write to the Free Software Foundation, Inc.,
+51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA
+*/
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use serde::Deserialize;
+
+/// A single routing rule. All of the `Some` predicates on a rule must match an alert group for
+/// that group to be sent to `webhook`; rules are evaluated in file order and the first match
+/// wins. A rule with no predicates at all matches everything, so it's usually what you want as
+/// the last entry instead of relying on `default_webhook`.
+#[derive(Debug, Deserialize)]
+pub struct RoutingRule {
+    /// Friendly name used in `/readyz` output and logs. Defaults to the rule's position if unset.
+    pub name: Option<String>,
+    pub webhook: String,
+    pub receiver: Option<String>,
+    pub status: Option<String>,
+    /// Matches when `commonLabels[label_key] == label_value`.
+    pub label_key: Option<String>,
+    pub label_value: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoutingConfig {
+    /// Where alert groups go when no rule matches.
+    pub default_webhook: String,
+    #[serde(default)]
+    pub rules: Vec<RoutingRule>,
+}
+
+#[derive(Debug)]
+pub enum RoutingConfigError {
+    Io(std::io::Error),
+    UnsupportedExtension(String),
+    Toml(toml::de::Error),
+    Yaml(serde_yaml::Error),
+}
+
+impl std::fmt::Display for RoutingConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoutingConfigError::Io(e) => write!(f, "could not read routing config: {}", e),
+            RoutingConfigError::UnsupportedExtension(ext) => {
+                write!(f, "unsupported routing config extension: {}", ext)
+            }
+            RoutingConfigError::Toml(e) => write!(f, "could not parse routing config as TOML: {}", e),
+            RoutingConfigError::Yaml(e) => write!(f, "could not parse routing config as YAML: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RoutingConfigError {}
+
+impl RoutingConfig {
+    /// Builds a config with no rules at all, just a single catch-all webhook. This is what
+    /// pre-routing deployments looked like before this config existed: every alert, of every
+    /// status and severity, went to one `DISCORD_WEBHOOK`. Used as a fallback when
+    /// `ROUTING_CONFIG_PATH` isn't set, so an existing single-webhook deployment doesn't need a
+    /// config file just to keep working.
+    pub fn single_webhook(webhook: String) -> Self {
+        Self {
+            default_webhook: webhook,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Loads a routing config from `path`. The format (TOML or YAML) is inferred from the file
+    /// extension: `.toml` for TOML, `.yml`/`.yaml` for YAML.
+    pub fn load(path: &str) -> Result<Self, RoutingConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(RoutingConfigError::Io)?;
+
+        match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(RoutingConfigError::Toml),
+            Some("yml") | Some("yaml") => {
+                serde_yaml::from_str(&contents).map_err(RoutingConfigError::Yaml)
+            }
+            other => Err(RoutingConfigError::UnsupportedExtension(
+                other.unwrap_or("<none>").to_string(),
+            )),
+        }
+    }
+
+    /// Returns the webhook URL that `receiver`/`status`/`common_labels` should be dispatched to:
+    /// the first matching rule, or `default_webhook` if nothing matches.
+    pub fn route(
+        &self,
+        receiver: &str,
+        status: &str,
+        common_labels: &HashMap<String, String>,
+    ) -> &str {
+        for rule in &self.rules {
+            if let Some(r) = &rule.receiver {
+                if r != receiver {
+                    continue;
+                }
+            }
+
+            if let Some(s) = &rule.status {
+                if s != status {
+                    continue;
+                }
+            }
+
+            if let Some(key) = &rule.label_key {
+                let expected = rule.label_value.as_deref().unwrap_or_default();
+                match common_labels.get(key) {
+                    Some(actual) if actual == expected => {}
+                    _ => continue,
+                }
+            }
+
+            return &rule.webhook;
+        }
+
+        &self.default_webhook
+    }
+
+    /// Every distinct (name, webhook URL) this config could dispatch to, for `/readyz` probing.
+    /// The default fallback is included, labeled `"default"`, unless a rule already uses the same
+    /// URL, in which case it is probed once under that rule's name.
+    pub fn named_webhooks(&self) -> Vec<(String, &str)> {
+        let mut seen = std::collections::HashSet::new();
+        let mut out = Vec::new();
+
+        for (i, rule) in self.rules.iter().enumerate() {
+            if seen.insert(rule.webhook.as_str()) {
+                let name = rule.name.clone().unwrap_or_else(|| format!("rule {}", i));
+                out.push((name, rule.webhook.as_str()));
+            }
+        }
+
+        if seen.insert(self.default_webhook.as_str()) {
+            out.push(("default".to_string(), self.default_webhook.as_str()));
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RoutingConfig {
+        RoutingConfig {
+            default_webhook: "https://default.example/webhook".to_string(),
+            rules: vec![
+                RoutingRule {
+                    name: Some("critical".to_string()),
+                    webhook: "https://critical.example/webhook".to_string(),
+                    receiver: None,
+                    status: None,
+                    label_key: Some("severity".to_string()),
+                    label_value: Some("critical".to_string()),
+                },
+                RoutingRule {
+                    name: Some("team-a".to_string()),
+                    webhook: "https://team-a.example/webhook".to_string(),
+                    receiver: Some("team-a".to_string()),
+                    status: None,
+                    label_key: None,
+                    label_value: None,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn route_matches_first_rule_in_order() {
+        let labels = HashMap::from([("severity".to_string(), "critical".to_string())]);
+        assert_eq!(
+            config().route("team-a", "firing", &labels),
+            "https://critical.example/webhook"
+        );
+    }
+
+    #[test]
+    fn route_matches_by_receiver_when_label_rule_does_not_apply() {
+        let labels = HashMap::new();
+        assert_eq!(
+            config().route("team-a", "firing", &labels),
+            "https://team-a.example/webhook"
+        );
+    }
+
+    #[test]
+    fn route_falls_back_to_default_webhook() {
+        let labels = HashMap::new();
+        assert_eq!(
+            config().route("team-b", "firing", &labels),
+            "https://default.example/webhook"
+        );
+    }
+}
@@ -0,0 +1,210 @@
+/*
+This file is part of Alertmanager to Discord Bridge (https://github.com/SriRamanujam/alertmanager-discord-bridge)
+Copyright (C) 2021 Sri Ramanujam
+
+This program is free software; you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation; either version 2 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along
+with this program; if not, write to the Free Software Foundation, Inc.,
+51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA
+*/
+
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection};
+
+/// Tracks, per Alertmanager `groupKey`, the Discord message ID of the embed we posted for a
+/// firing alert group, so a later `resolved` payload for the same group can edit that message
+/// in place instead of posting a brand-new one.
+///
+/// Wrapped in a `Mutex` because `rusqlite::Connection` is not `Sync`, and this is shared across
+/// actix-web worker threads via `app_data`.
+pub struct AlertStateStore {
+    conn: Mutex<Connection>,
+    ttl_secs: i64,
+}
+
+/// A dispatch job as it's stored on disk, before it's been turned back into a `queue::DispatchJob`.
+/// Kept generic over plain columns (rather than depending on the `queue` module's types) so
+/// `store` doesn't need to know about `queue`.
+pub struct PersistedDispatchJob {
+    pub id: i64,
+    pub webhook: String,
+    pub kind: String,
+    pub message_id: Option<String>,
+    pub group_key: String,
+    pub payload: String,
+    pub status: String,
+    /// JSON-encoded `Vec<String>`, same as `payload`'s JSON encoding.
+    pub severities: String,
+}
+
+impl AlertStateStore {
+    /// Opens (creating if necessary) the SQLite database at `path` and ensures the schema
+    /// exists. `alert_state` rows older than `ttl_secs` are purged opportunistically whenever a
+    /// mapping is recorded or looked up, so the database doesn't grow unbounded even if a
+    /// `resolved` payload never arrives for a group.
+    pub fn open(path: &str, ttl_secs: i64) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS alert_state (
+                group_key  TEXT PRIMARY KEY,
+                message_id TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS dispatch_queue (
+                id         INTEGER PRIMARY KEY AUTOINCREMENT,
+                webhook    TEXT NOT NULL,
+                kind       TEXT NOT NULL,
+                message_id TEXT,
+                group_key  TEXT NOT NULL,
+                payload    TEXT NOT NULL,
+                status     TEXT NOT NULL,
+                severities TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            ttl_secs,
+        })
+    }
+
+    /// Records that `group_key` is currently represented by Discord message `message_id`.
+    /// Overwrites any prior entry for the same group, since a fresh firing payload supersedes it.
+    pub fn record_firing(&self, group_key: &str, message_id: &str) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        self.purge_expired(&conn)?;
+
+        conn.execute(
+            "INSERT INTO alert_state (group_key, message_id, created_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(group_key) DO UPDATE SET message_id = excluded.message_id, created_at = excluded.created_at",
+            params![group_key, message_id, now()],
+        )?;
+
+        Ok(())
+    }
+
+    /// Looks up the message ID for `group_key`, if we still have one on record. Does not clear
+    /// the row: the caller only knows a patch *should* happen, not that it has. Call
+    /// `clear_message_id` once the patch actually succeeds.
+    pub fn peek_message_id(&self, group_key: &str) -> rusqlite::Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        self.purge_expired(&conn)?;
+
+        Ok(conn
+            .query_row(
+                "SELECT message_id FROM alert_state WHERE group_key = ?1",
+                params![group_key],
+                |row| row.get::<_, String>(0),
+            )
+            .ok())
+    }
+
+    /// Clears the mapping for `group_key`, since a resolution consumes the lifecycle: the next
+    /// firing of this group should start a fresh message. Called once the patch that resolves the
+    /// group has actually succeeded, or once that patch has been abandoned after exhausting its
+    /// retries.
+    ///
+    /// `message_id` must match the row's current value: if the group re-fired (and got a new
+    /// message id recorded) while a stale patch for the old message was still retrying, clearing
+    /// by `group_key` alone would delete the mapping for the *new*, still-live message instead.
+    pub fn clear_message_id(&self, group_key: &str, message_id: &str) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM alert_state WHERE group_key = ?1 AND message_id = ?2",
+            params![group_key, message_id],
+        )?;
+        Ok(())
+    }
+
+    fn purge_expired(&self, conn: &Connection) -> rusqlite::Result<()> {
+        conn.execute(
+            "DELETE FROM alert_state WHERE created_at < ?1",
+            params![now() - self.ttl_secs],
+        )?;
+
+        Ok(())
+    }
+
+    /// Persists a job to the retry queue before handing it to the in-process dispatch worker, so
+    /// it survives a restart if the process dies before the worker gets to it. `severities` is
+    /// expected to already be JSON-encoded, same as `payload`. Returns the row's id, used later
+    /// to remove it once dispatch succeeds (or is abandoned).
+    #[allow(clippy::too_many_arguments)]
+    pub fn enqueue_dispatch(
+        &self,
+        webhook: &str,
+        kind: &str,
+        message_id: Option<&str>,
+        group_key: &str,
+        payload: &str,
+        status: &str,
+        severities: &str,
+    ) -> rusqlite::Result<i64> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            "INSERT INTO dispatch_queue (webhook, kind, message_id, group_key, payload, status, severities, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![webhook, kind, message_id, group_key, payload, status, severities, now()],
+        )?;
+
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Every job still sitting in the retry queue, oldest first. Used at startup to resume
+    /// dispatches that were pending when the process last stopped.
+    pub fn pending_dispatches(&self) -> rusqlite::Result<Vec<PersistedDispatchJob>> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut stmt = conn.prepare(
+            "SELECT id, webhook, kind, message_id, group_key, payload, status, severities
+             FROM dispatch_queue ORDER BY id ASC",
+        )?;
+
+        let rows = stmt.query_map([], |row| {
+            Ok(PersistedDispatchJob {
+                id: row.get(0)?,
+                webhook: row.get(1)?,
+                kind: row.get(2)?,
+                message_id: row.get(3)?,
+                group_key: row.get(4)?,
+                payload: row.get(5)?,
+                status: row.get(6)?,
+                severities: row.get(7)?,
+            })
+        })?;
+
+        rows.collect()
+    }
+
+    /// Removes a job from the retry queue: either it dispatched successfully, or it exhausted its
+    /// retries and is being dropped.
+    pub fn remove_dispatch(&self, id: i64) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM dispatch_queue WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}